@@ -0,0 +1,59 @@
+use std::fs;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config_dir;
+
+// Global settings loaded from config.yaml in the ducky config dir.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Config {
+    pub temperature: Option<f64>,
+    pub proxy: Option<String>,
+    pub api_key_env: Option<String>,
+
+    #[serde(default = "default_true")]
+    pub save: bool,
+
+    #[serde(default = "default_true")]
+    pub highlight: bool,
+
+    // When set, print the assembled context instead of calling the API.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            temperature: None,
+            proxy: None,
+            api_key_env: None,
+            save: default_true(),
+            highlight: default_true(),
+            dry_run: false,
+        }
+    }
+}
+
+impl Config {
+    // load reads config.yaml from the ducky config dir, falling back to
+    // Config::default() if it does not exist.
+    pub fn load() -> Result<Config> {
+        let mut path = config_dir()?;
+        path.push("config.yaml");
+
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = fs::read_to_string(path)?;
+        let config: Config = serde_yaml::from_str(&contents)?;
+
+        Ok(config)
+    }
+}
@@ -1,5 +1,10 @@
+mod config;
 mod namespace;
+mod roles;
+mod tokens;
+mod vision;
 
+use crate::config::Config;
 use crate::namespace::Namespace;
 
 use std::fs;
@@ -8,16 +13,27 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 use bat::PrettyPrinter;
-use chatgpt::types::Role;
+use chatgpt::types::{ResponseChunk, Role};
 use clap::Parser;
 use directories::BaseDirs;
+use futures_util::StreamExt;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 use sha2::Digest;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Arg {
+    #[clap(long)]
+    /// Override the API base URL for this conversation, e.g. for Azure OpenAI or a
+    /// local llama.cpp/ollama OpenAI-compatible server
+    api_base: Option<String>,
+
     #[clap(short, long)]
     /// The conversation to send the chat with
     conversation: Option<String>,
@@ -26,6 +42,12 @@ struct Arg {
     /// Open EDITOR to enter the prompt
     editor: bool,
 
+    #[clap(long)]
+    /// Attach a local image file to the prompt (repeatable). A new conversation
+    /// starts on a vision-capable engine unless --set-engine is given; a loaded
+    /// conversation keeps the engine it was saved with.
+    image: Vec<PathBuf>,
+
     #[clap(short, long)]
     /// Keep the message to send as context with each prompt
     keep: bool,
@@ -43,6 +65,10 @@ struct Arg {
     /// Open up a repl
     repl: bool,
 
+    #[clap(long)]
+    /// Seed the conversation with a named system-prompt preset from roles.yaml
+    role: Option<String>,
+
     #[clap(long)]
     /// Sets the default engine for the conversation
     set_engine: Option<String>,
@@ -51,6 +77,10 @@ struct Arg {
     #[clap(short, long)]
     system: bool,
 
+    #[clap(long)]
+    /// Stream the response token by token instead of waiting for the full reply
+    stream: bool,
+
     /// The prompt to be sent to GPT
     prompt: Vec<String>,
 }
@@ -66,10 +96,9 @@ fn is_git_repo(dir: &Path) -> bool {
     output.status.success()
 }
 
-/// Returns the config path and ensures the config
-/// directory exists.
-pub fn config_path(name: &str) -> Result<PathBuf> {
-    let mut config_file_path = match BaseDirs::new() {
+/// Returns the ducky config directory, creating it if it does not exist yet.
+pub fn config_dir() -> Result<PathBuf> {
+    match BaseDirs::new() {
         Some(base_dirs) => {
             let config_dir_base = base_dirs.config_dir();
             let mut config_dir = PathBuf::from(config_dir_base);
@@ -77,15 +106,21 @@ pub fn config_path(name: &str) -> Result<PathBuf> {
 
             if !config_dir.exists() {
                 match std::fs::create_dir_all(&config_dir) {
-                    Ok(_) => config_dir,
-                    Err(e) => return Err(anyhow!("{}", e)),
+                    Ok(_) => Ok(config_dir),
+                    Err(e) => Err(anyhow!("{}", e)),
                 }
             } else {
-                config_dir
+                Ok(config_dir)
             }
         }
-        None => return Err(anyhow!("Unable to get config directory")),
-    };
+        None => Err(anyhow!("Unable to get config directory")),
+    }
+}
+
+/// Returns the config path and ensures the config
+/// directory exists.
+pub fn config_path(name: &str) -> Result<PathBuf> {
+    let mut config_file_path = config_dir()?;
 
     config_file_path.push(name.to_owned());
     config_file_path.set_extension("json");
@@ -104,17 +139,33 @@ pub fn config_path(name: &str) -> Result<PathBuf> {
 //     "gpt-4-32k-0314",
 // ];
 
-fn start_conversation(name: Option<String>, key: &str, arg: &Arg) -> Result<Namespace> {
+fn start_conversation(
+    name: Option<String>,
+    key: &str,
+    arg: &Arg,
+    config: &Config,
+) -> Result<Namespace> {
+    let default_model = if arg.image.is_empty() {
+        "gpt-3.5-turbo"
+    } else {
+        "gpt-4-vision-preview"
+    };
+
     let state = if let Some(model) = &arg.set_engine {
-        Namespace::create(name, &model, key)?
+        Namespace::create(name, &model, key, config, arg.api_base.clone())?
     } else {
-        Namespace::create(name, "gpt-3.5-turbo", key)?
+        Namespace::create(name, default_model, key, config, arg.api_base.clone())?
     };
 
     Ok(state)
 }
 
-fn load_or_start_conversation(key: &str, name: Option<String>, arg: &Arg) -> Result<Namespace> {
+fn load_or_start_conversation(
+    key: &str,
+    name: Option<String>,
+    arg: &Arg,
+    config: &Config,
+) -> Result<Namespace> {
     match name {
         Some(name) => {
             let config_file_path = config_path(&name)?;
@@ -132,15 +183,15 @@ fn load_or_start_conversation(key: &str, name: Option<String>, arg: &Arg) -> Res
             };
 
             if !config_file_path.exists() {
-                let client = start_conversation(Some(name), key, arg)?;
+                let client = start_conversation(Some(name), key, arg, config)?;
                 return Ok(client);
             }
 
-            let conv = Namespace::load_from(config_file_path.as_path(), Some(name), key)?;
+            let conv = Namespace::load_from(config_file_path.as_path(), Some(name), key, config)?;
             return Ok(conv);
         }
         None => {
-            return start_conversation(None, key, arg);
+            return start_conversation(None, key, arg, config);
         }
     }
 }
@@ -214,15 +265,21 @@ fn edit_text(text: &str) -> Result<String> {
 }
 
 fn conversation_prompt(args: &Arg) -> Result<String> {
-    if args.editor {
-        return edit_text("");
-    }
+    let prompt = if args.editor {
+        edit_text("")?
+    } else {
+        args.prompt.join(" ").trim().to_string()
+    };
 
-    let prompt = args.prompt.join(" ");
-    Ok(prompt.trim().to_string())
+    Ok(prompt)
 }
 
-fn print_markdown(markdown: &str) -> Result<()> {
+fn print_markdown(markdown: &str, highlight: bool) -> Result<()> {
+    if !highlight {
+        println!("{}", markdown);
+        return Ok(());
+    }
+
     let mut printer = PrettyPrinter::new();
     printer.input_from_bytes(markdown.as_bytes());
     printer.language("markdown");
@@ -236,22 +293,188 @@ fn print_markdown(markdown: &str) -> Result<()> {
     Ok(())
 }
 
-async fn repl(state: &mut Namespace) -> Result<()> {
-    let mut editor = DefaultEditor::new()?;
+const REPL_COMMANDS: &[&str] = &[".set", ".session", ".clear", ".role", ".save", ".help"];
+
+// Tab-completes the repl's dot-commands and falls back to completing file
+// paths for everything else.
+struct ReplHelper {
+    files: FilenameCompleter,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if line.starts_with('.') {
+            let matches = REPL_COMMANDS
+                .iter()
+                .filter(|cmd| cmd.starts_with(line))
+                .map(|cmd| Pair {
+                    display: cmd.to_string(),
+                    replacement: cmd.to_string(),
+                })
+                .collect();
+            return Ok((0, matches));
+        }
+
+        self.files.complete(line, pos, ctx)
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+impl Highlighter for ReplHelper {}
+impl Validator for ReplHelper {}
+impl Helper for ReplHelper {}
+
+// Handles a `.`-prefixed repl command in place, mutating `state` as needed.
+// Returns Ok(false) if `line` doesn't start with `.`, in which case the
+// caller should send it to the model as a normal prompt. An unknown
+// `.`-command is reported on stderr and still returns Ok(true).
+async fn dispatch_repl_command(
+    line: &str,
+    state: &mut Namespace,
+    key: &str,
+    config: &Config,
+) -> Result<bool> {
+    if !line.starts_with('.') {
+        return Ok(false);
+    }
+
+    let mut parts = line[1..].split_whitespace();
+    match parts.next().unwrap_or("") {
+        "set" => match (parts.next(), parts.next()) {
+            (Some("temperature"), Some(value)) => match value.parse::<f64>() {
+                Ok(temperature) => state.set_temperature(key, config, temperature)?,
+                Err(_) => eprintln!("Invalid temperature: {}", value),
+            },
+            (Some(field), _) => eprintln!("Unknown setting: {}", field),
+            _ => eprintln!("Usage: .set <field> <value>"),
+        },
+        "session" => match parts.next() {
+            Some(name) => {
+                let config_file_path = config_path(name)?;
+                *state = if config_file_path.exists() {
+                    Namespace::load_from(&config_file_path, Some(name.to_string()), key, config)?
+                } else {
+                    Namespace::create(
+                        Some(name.to_string()),
+                        &state.data.model.clone(),
+                        key,
+                        config,
+                        None,
+                    )?
+                };
+                println!("Switched to session '{}'.", name);
+            }
+            None => eprintln!("Usage: .session <name>"),
+        },
+        "clear" => {
+            state.data.context.clear();
+            println!("Context cleared.");
+        }
+        "role" => match parts.next() {
+            Some(name) => {
+                let roles = roles::load_roles()?;
+                let role = roles::find_role(&roles, name)?;
+                state
+                    .send_system_message(role.prompt.clone(), true, true)
+                    .await?;
+                println!("Applied role '{}'.", name);
+            }
+            None => eprintln!("Usage: .role <name>"),
+        },
+        "save" => match &state.name {
+            Some(name) => {
+                let config_file_path = config_path(name)?;
+                state.store(&config_file_path)?;
+                println!("Saved session '{}'.", name);
+            }
+            None => eprintln!("No named session to save; start one with -c <name>."),
+        },
+        "help" => {
+            println!(".set <field> <value>  Change a setting (e.g. `.set temperature 0.2`)");
+            println!(".session <name>       Switch to (or start) a named session");
+            println!(".clear                Reset the kept conversation context");
+            println!(".role <name>          Seed the conversation with a role from roles.yaml");
+            println!(".save                 Force-save the current session");
+            println!(".help                 Show this message");
+        }
+        other => eprintln!("Unknown command: .{}. Type .help for a list of commands.", other),
+    }
+
+    Ok(true)
+}
+
+// Drives a streaming exchange: prints each delta as plain text as it
+// arrives (bat needs the full buffer to highlight), then optionally
+// re-renders the finished message through `print_markdown`.
+async fn send_streaming(
+    state: &mut Namespace,
+    prompt: String,
+    keep: bool,
+    extend_session: bool,
+    highlight: bool,
+) -> Result<()> {
+    let (mut stream, context_len) = state.send_message_streaming(prompt, extend_session).await?;
+
+    let mut content = String::new();
+    while let Some(chunk) = stream.next().await {
+        if let ResponseChunk::Content { delta, .. } = chunk? {
+            print!("{}", delta);
+            std::io::stdout().flush()?;
+            content.push_str(&delta);
+        }
+    }
+    println!();
+
+    state.finish_streaming_message(content.clone(), keep, context_len);
+
+    if highlight {
+        print_markdown(&content, highlight)?;
+    }
+
+    Ok(())
+}
 
-    let mut convo = state.create_conversation();
+async fn repl(state: &mut Namespace, key: &str, config: &Config, stream: bool) -> Result<()> {
+    let mut editor: Editor<ReplHelper, FileHistory> = Editor::new()?;
+    editor.set_helper(Some(ReplHelper {
+        files: FilenameCompleter::new(),
+    }));
 
     println!(
-        "Welcome to ChatGPT! Type your message below to start chatting, or type 'exit' to quit."
+        "Welcome to ChatGPT! Type your message below to start chatting, 'exit' to quit, or \
+         .help for a list of commands."
     );
+
     loop {
         match editor.readline("> ") {
             Ok(line) => {
+                let line = line.trim().to_string();
                 if line == "exit" {
                     break;
                 }
-                let response = convo.send_message(line.trim()).await?;
-                print_markdown(&response.message().content)?;
+                if line.is_empty() {
+                    continue;
+                }
+
+                if dispatch_repl_command(&line, state, key, config).await? {
+                    continue;
+                }
+
+                if stream {
+                    send_streaming(state, line, false, true, config.highlight).await?;
+                } else {
+                    let response = state.send_message(line, false, true).await?;
+                    print_markdown(&response.message().content, config.highlight)?;
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -268,12 +491,11 @@ async fn repl(state: &mut Namespace) -> Result<()> {
         }
     }
 
-    // Ensure we update state history before storing it
-    drop(convo);
-
-    if let Some(name) = &state.name {
-        let config_file_path = config_path(&name)?;
-        state.store(&config_file_path)?;
+    if config.save {
+        if let Some(name) = &state.name {
+            let config_file_path = config_path(&name)?;
+            state.store(&config_file_path)?;
+        }
     }
 
     Ok(())
@@ -282,14 +504,48 @@ async fn repl(state: &mut Namespace) -> Result<()> {
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Arg::parse();
+    let mut config = Config::load()?;
 
-    let key = std::env::var("DUCKY_GPT_KEY").expect("No key found. Please set DUCKY_GPT_KEY.");
+    let role = match &args.role {
+        Some(name) => {
+            let roles = roles::load_roles()?;
+            let role = roles::find_role(&roles, name)?;
+            if role.temperature.is_some() {
+                config.temperature = role.temperature;
+            }
+            Some(role)
+        }
+        None => None,
+    };
+
+    let key_env = config.api_key_env.as_deref().unwrap_or("DUCKY_GPT_KEY");
+    let key = std::env::var(key_env)
+        .unwrap_or_else(|_| panic!("No key found. Please set {}.", key_env));
     let session = conversation_name(&args)?;
 
-    let mut state = load_or_start_conversation(&key, session, &args)?;
+    let mut state = load_or_start_conversation(&key, session, &args, &config)?;
+
+    if let Some(role) = &role {
+        // Roles are meant to be persisted as the conversation's kept system
+        // message, not re-appended on every invocation: skip seeding it again
+        // if it's already the first kept message in a loaded/persisted
+        // namespace.
+        let already_seeded = state
+            .data
+            .context
+            .first()
+            .map(|m| m.role == Role::System && m.content == role.prompt)
+            .unwrap_or(false);
+
+        if !already_seeded {
+            state
+                .send_system_message(role.prompt.clone(), true, args.persist)
+                .await?;
+        }
+    }
 
     if args.repl {
-        repl(&mut state).await?;
+        repl(&mut state, &key, &config, args.stream).await?;
         return Ok(());
     }
 
@@ -327,19 +583,30 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let response = if args.system {
-        state
-            .send_system_message(prompt, args.keep, args.persist)
-            .await?
+    let prompt = vision::build_message_content(&prompt, &args.image)?;
+
+    // Vision replies are sent through Namespace::send_vision_request, which
+    // doesn't stream; route image-attached prompts through the non-streaming
+    // path even if --stream was passed.
+    if args.stream && !args.system && args.image.is_empty() {
+        send_streaming(&mut state, prompt, args.keep, args.persist, config.highlight).await?;
     } else {
-        state.send_message(prompt, args.keep, args.persist).await?
-    };
+        let response = if args.system {
+            state
+                .send_system_message(prompt, args.keep, args.persist)
+                .await?
+        } else {
+            state.send_message(prompt, args.keep, args.persist).await?
+        };
 
-    print_markdown(&response.message().content)?;
+        print_markdown(&response.message().content, config.highlight)?;
+    }
 
-    if let Some(name) = &state.name {
-        let config_file_path = config_path(&name)?;
-        state.store(&config_file_path)?;
+    if config.save {
+        if let Some(name) = &state.name {
+            let config_file_path = config_path(&name)?;
+            state.store(&config_file_path)?;
+        }
     }
 
     Ok(())
@@ -3,28 +3,79 @@ use std::{
     fs::File,
     io::{Read, Write},
     path::Path,
+    pin::Pin,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use chatgpt::{
-    prelude::{ChatGPT, ChatGPTEngine, Conversation, ModelConfigurationBuilder},
-    types::{ChatMessage, CompletionResponse, Role},
+    prelude::{ChatGPT, ChatGPTEngine, ModelConfigurationBuilder},
+    types::{ChatMessage, CompletionResponse, ResponseChunk, Role},
 };
+use futures_util::Stream;
 use serde::{Deserialize, Serialize};
 
+use crate::config::Config;
+use crate::tokens::{is_vision_model, reserved_completion_tokens, trim_history_to_budget, VISION_MAX_TOKENS};
+
 #[derive(Serialize, Deserialize)]
 pub struct ConversationData {
     pub model: String,
     pub history: Vec<ChatMessage>,
     pub context: Vec<ChatMessage>,
-    pub includes: usize,
-    pub session_len: usize,
+
+    /// Per-conversation API base URL override, e.g. for an Azure OpenAI
+    /// deployment or a local llama.cpp/ollama OpenAI-compatible server.
+    /// Falls back to the global `proxy` setting in config.yaml when unset.
+    #[serde(default)]
+    pub api_base: Option<String>,
 }
 
 pub struct Namespace {
     client: ChatGPT,
     pub name: Option<String>,
     pub data: ConversationData,
+    dry_run: bool,
+    key: String,
+    // Resolved api_base (conversation override, else config.proxy, else
+    // the provider's default), kept alongside `client` so `send_vision_request`
+    // can reach the same endpoint `client` would without access to its
+    // internals.
+    resolved_api_base: Option<String>,
+}
+
+// Builds the chatgpt client config from the engine, the user's global
+// config.yaml settings (temperature, proxy/api base) and an optional
+// per-conversation api_base override, which takes precedence over the
+// global proxy setting. Returns the resolved api_base alongside the client.
+fn build_client(
+    engine: ChatGPTEngine,
+    model: &str,
+    key: &str,
+    config: &Config,
+    api_base: Option<&str>,
+) -> Result<(ChatGPT, Option<String>)> {
+    let mut builder = ModelConfigurationBuilder::default();
+    builder.engine(engine);
+
+    if let Some(temperature) = config.temperature {
+        builder.temperature(temperature as f32);
+    }
+
+    // Vision replies need more room than the default completion budget to
+    // describe an image in any useful detail. tokens::reserved_completion_tokens
+    // reserves the same VISION_MAX_TOKENS budget when trimming history, so the
+    // two stay in sync.
+    if is_vision_model(model) {
+        builder.max_tokens(VISION_MAX_TOKENS as u32);
+    }
+
+    let api_base = api_base.or(config.proxy.as_deref()).map(|s| s.to_string());
+    if let Some(api_base) = &api_base {
+        builder.api_url(url::Url::parse(api_base)?);
+    }
+
+    let client = ChatGPT::new_with_config(key, builder.build().unwrap())?;
+    Ok((client, api_base))
 }
 
 impl Namespace {
@@ -38,42 +89,40 @@ impl Namespace {
     }
 
     // load will read in an existing conversation
-    pub fn load_from(path: &Path, name: Option<String>, key: &str) -> Result<Self> {
+    pub fn load_from(path: &Path, name: Option<String>, key: &str, config: &Config) -> Result<Self> {
         let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
 
         let conv: ConversationData = serde_json::from_str(&contents)?;
 
-        let model = engine_from_str(&conv.model)?;
-        let client = ChatGPT::new_with_config(
-            key,
-            ModelConfigurationBuilder::default()
-                .engine(model)
-                .build()
-                .unwrap(),
-        )?;
+        let engine = engine_from_str(&conv.model)?;
+        let (client, resolved_api_base) =
+            build_client(engine, &conv.model, key, config, conv.api_base.as_deref())?;
 
         Ok(Namespace {
             client,
             name,
             data: conv,
+            dry_run: config.dry_run,
+            key: key.to_string(),
+            resolved_api_base,
         })
     }
 
     // create a new state and initialize the gpt client
-    pub fn create(name: Option<String>, engine: &str, key: &str) -> Result<Self> {
-        let model = engine_from_str(engine)?;
-        let client = ChatGPT::new_with_config(
-            key,
-            ModelConfigurationBuilder::default()
-                .engine(model)
-                .build()
-                .unwrap(),
-        )?;
+    pub fn create(
+        name: Option<String>,
+        engine: &str,
+        key: &str,
+        config: &Config,
+        api_base: Option<String>,
+    ) -> Result<Self> {
+        let chat_engine = engine_from_str(engine)?;
+        let (client, resolved_api_base) =
+            build_client(chat_engine, engine, key, config, api_base.as_deref())?;
 
         // TODO support a first system message
-        // TODO have a way to change the number of includes
         Ok(Namespace {
             client,
             name,
@@ -81,9 +130,11 @@ impl Namespace {
                 model: engine.to_string(),
                 history: vec![],
                 context: vec![],
-                includes: 2,
-                session_len: 0,
+                api_base,
             },
+            dry_run: config.dry_run,
+            key: key.to_string(),
+            resolved_api_base,
         })
     }
 
@@ -99,27 +150,54 @@ impl Namespace {
             role,
         };
 
-        if !extend_session {
-            self.data.session_len = 0;
-        }
-
-        // Include both the assistant's response and the user's message for each "includes".
-        let includes = (self.data.includes + self.data.session_len) * 2;
-
         self.data.history.push(message.clone());
 
-        let history_len = if self.data.history.len() <= includes + 1 {
-            0
-        } else {
-            self.data.history.len() - 1 - includes
-        };
         let context_len = self.data.context.len();
 
-        self.data
-            .context
-            .extend_from_slice(&mut self.data.history[history_len..]);
+        if extend_session {
+            let window = trim_history_to_budget(
+                &self.data.context,
+                &self.data.history,
+                &self.data.model,
+                reserved_completion_tokens(&self.data.model),
+            );
+            self.data.context.extend(window);
+        } else {
+            self.data.context.push(message.clone());
+        }
+
+        let response = if self.dry_run {
+            println!(
+                "--- dry run: assembled context ({} messages) ---",
+                self.data.context.len()
+            );
+            for msg in &self.data.context {
+                println!("[{:?}] {}", msg.role, msg.content);
+            }
 
-        let response = self.client.send_history(&self.data.context).await?;
+            // Stand in for the API response so the rest of the bookkeeping below
+            // (history and context pruning) behaves exactly as it would on a real call.
+            serde_json::from_value(serde_json::json!({
+                "id": "dry-run",
+                "object": "chat.completion",
+                "created": 0,
+                "model": self.data.model,
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "" },
+                    "finish_reason": "stop",
+                }],
+                "usage": { "prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0 },
+            }))?
+        } else if is_vision_model(&self.data.model) {
+            // `ChatGPT::send_history` sends `ChatMessage::content` as a plain
+            // string, which can't carry the structured image content parts a
+            // vision-capable model expects. Bypass the client entirely and
+            // POST the chat completions request ourselves.
+            self.send_vision_request().await?
+        } else {
+            self.client.send_history(&self.data.context).await?
+        };
 
         self.data.history.push(response.message().clone());
         let last_user = self.data.context.pop().unwrap();
@@ -142,13 +220,57 @@ impl Namespace {
             }
         }
 
-        if extend_session {
-            self.data.session_len += 1;
-        }
-
         Ok(response)
     }
 
+    // send_vision_request POSTs `self.data.context` to the chat completions
+    // endpoint directly, bypassing the chatgpt crate's `send_history`. Each
+    // message's content is parsed as a JSON content-parts array only when it
+    // has the shape `vision::build_message_content` actually produces (see
+    // `vision::is_content_parts`); plain text that merely happens to parse
+    // as JSON (a bare number, "true"/"null", a pasted snippet) is still sent
+    // as a plain string. The response is deserialized straight into
+    // `CompletionResponse`, which matches the standard chat completion JSON
+    // shape already relied on by the dry-run stub above.
+    async fn send_vision_request(&self) -> Result<CompletionResponse> {
+        let messages: Vec<serde_json::Value> = self
+            .data
+            .context
+            .iter()
+            .map(|msg| {
+                let content = if crate::vision::is_content_parts(&msg.content) {
+                    serde_json::from_str(&msg.content)
+                        .unwrap_or_else(|_| serde_json::Value::String(msg.content.clone()))
+                } else {
+                    serde_json::Value::String(msg.content.clone())
+                };
+                serde_json::json!({ "role": msg.role, "content": content })
+            })
+            .collect();
+
+        let body = serde_json::json!({
+            "model": self.data.model,
+            "messages": messages,
+            "max_tokens": VISION_MAX_TOKENS,
+        });
+
+        let base = self
+            .resolved_api_base
+            .as_deref()
+            .unwrap_or("https://api.openai.com/v1");
+        let url = format!("{}/chat/completions", base.trim_end_matches('/'));
+
+        let response = reqwest::Client::new()
+            .post(url)
+            .bearer_auth(&self.key)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(response.json().await?)
+    }
+
     // send_system_message sends a message as a system message and keeps the system
     // message in the context.
     pub async fn send_system_message<S: Into<String>>(
@@ -174,43 +296,91 @@ impl Namespace {
             .await;
     }
 
-    // function to create a ChatGPT conversation using context as the initial history.
-    // To return history,
-    pub fn create_conversation(&mut self) -> NamespaceConversation {
-        NamespaceConversation {
-            conversation: Conversation::new_with_history(
-                self.client.clone(),
-                self.data.context.clone(),
-            ),
-            space: self,
+    // send_message_streaming begins a streaming exchange: the user message is
+    // recorded into history/context immediately and a stream of response
+    // deltas is returned for the caller to print as they arrive. Once the
+    // stream is exhausted, call `finish_streaming_message` with the
+    // assembled reply to commit the same history/context bookkeeping that
+    // `send_message_as` does for non-streaming replies.
+    pub async fn send_message_streaming<S: Into<String>>(
+        &mut self,
+        message: S,
+        extend_session: bool,
+    ) -> Result<(Pin<Box<dyn Stream<Item = Result<ResponseChunk>> + Send>>, usize)> {
+        let message = ChatMessage {
+            content: message.into(),
+            role: Role::User,
+        };
+
+        self.data.history.push(message.clone());
+
+        let context_len = self.data.context.len();
+
+        if extend_session {
+            let window = trim_history_to_budget(
+                &self.data.context,
+                &self.data.history,
+                &self.data.model,
+                reserved_completion_tokens(&self.data.model),
+            );
+            self.data.context.extend(window);
+        } else {
+            self.data.context.push(message.clone());
         }
-    }
-}
 
-pub struct NamespaceConversation<'a> {
-    conversation: Conversation,
-    space: &'a mut Namespace,
-}
+        if self.dry_run {
+            println!(
+                "--- dry run: assembled context ({} messages) ---",
+                self.data.context.len()
+            );
+            for msg in &self.data.context {
+                println!("[{:?}] {}", msg.role, msg.content);
+            }
 
-impl<'a> NamespaceConversation<'a> {
-    pub async fn send_message<S: Into<String>>(
-        &mut self,
-        message: S,
-    ) -> Result<CompletionResponse> {
-        let r = self.conversation.send_message(message).await?;
-        Ok(r)
+            // No request goes out in dry-run mode, so the stream yields no
+            // deltas; `finish_streaming_message` still runs the same
+            // history/context bookkeeping as a real streamed reply would.
+            return Ok((Box::pin(futures_util::stream::empty()), context_len));
+        }
+
+        let stream = self
+            .client
+            .send_history_streaming(&self.data.context)
+            .await?;
+
+        Ok((Box::pin(stream), context_len))
     }
-}
 
-impl<'a> Drop for NamespaceConversation<'a> {
-    fn drop(&mut self) {
-        let mut history = self.conversation.history.to_owned();
+    // finish_streaming_message commits the assembled assistant reply to
+    // history/context, mirroring the keep/context-pruning logic in
+    // `send_message_as`.
+    pub fn finish_streaming_message(&mut self, content: String, keep: bool, context_len: usize) {
+        let response = ChatMessage {
+            content,
+            role: Role::Assistant,
+        };
+        self.data.history.push(response);
 
-        for _ in 0..self.space.data.context.len() {
-            history.remove(0);
+        let last_user = self.data.context.pop().unwrap();
+        self.data.context.truncate(context_len);
+        if keep {
+            self.data.context.push(last_user);
         }
+    }
 
-        self.space.data.history.append(&mut history);
+    // set_temperature rebuilds the underlying client with a new sampling
+    // temperature, e.g. from the repl's `.set temperature <value>` command.
+    // Takes the live Config so proxy/api_key_env/dry_run/save aren't lost.
+    pub fn set_temperature(&mut self, key: &str, config: &Config, temperature: f64) -> Result<()> {
+        let engine = engine_from_str(&self.data.model)?;
+        let mut config = config.clone();
+        config.temperature = Some(temperature);
+        let (client, resolved_api_base) =
+            build_client(engine, &self.data.model, key, &config, self.data.api_base.as_deref())?;
+        self.client = client;
+        self.resolved_api_base = resolved_api_base;
+
+        Ok(())
     }
 }
 
@@ -222,7 +392,22 @@ fn engine_from_str(s: &str) -> Result<ChatGPTEngine> {
         "gpt-4-0314" => Ok(ChatGPTEngine::Gpt4_0314),
         "gpt-4-32k-0314" => Ok(ChatGPTEngine::Gpt4_32k_0314),
 
-        custom => Err(anyhow!("Invalid model: {}", custom)),
-        // custom => Ok(ChatGPTEngine::Custom(custom.clone())),
+        // Anything else is assumed to be a model served by a custom/OpenAI-compatible
+        // endpoint (Azure OpenAI, a local llama.cpp/ollama server, etc.) configured
+        // via `api_base`/`proxy`. ChatGPTEngine::Custom holds a &'static str, not an
+        // owned String, so leak the model name once rather than per-call re-leaking.
+        custom => {
+            static LEAKED: std::sync::Mutex<Vec<&'static str>> = std::sync::Mutex::new(Vec::new());
+            let mut leaked = LEAKED.lock().unwrap();
+            let name = match leaked.iter().find(|name| **name == custom) {
+                Some(name) => *name,
+                None => {
+                    let name: &'static str = Box::leak(custom.to_string().into_boxed_str());
+                    leaked.push(name);
+                    name
+                }
+            };
+            Ok(ChatGPTEngine::Custom(name))
+        }
     }
 }
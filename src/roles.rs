@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config_dir;
+
+// A reusable system-prompt preset loaded from roles.yaml in the ducky
+// config dir.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Role {
+    pub name: String,
+    pub prompt: String,
+    pub temperature: Option<f64>,
+}
+
+// load_roles reads roles.yaml from the ducky config dir, returning an
+// empty map if it does not exist.
+pub fn load_roles() -> Result<HashMap<String, Role>> {
+    let mut path = config_dir()?;
+    path.push("roles.yaml");
+
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let contents = fs::read_to_string(path)?;
+    let roles: Vec<Role> = serde_yaml::from_str(&contents)?;
+
+    Ok(roles.into_iter().map(|role| (role.name.clone(), role)).collect())
+}
+
+// find_role looks up a role by name, erroring with the available names if it is missing.
+pub fn find_role(roles: &HashMap<String, Role>, name: &str) -> Result<Role> {
+    roles
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("No role named '{}'. Known roles: {:?}", name, roles.keys().collect::<Vec<_>>()))
+}
@@ -0,0 +1,172 @@
+use chatgpt::types::ChatMessage;
+
+// Fixed per-message overhead, mirroring tiktoken's num_tokens_from_messages.
+const PER_MESSAGE_OVERHEAD: usize = 4;
+
+// Tokens reserved for the reply when trimming history to fit the context window.
+pub const RESERVED_COMPLETION_TOKENS: usize = 512;
+
+// Reply budget ducky requests via max_tokens for vision completions; shared
+// with reserved_completion_tokens so the two stay in sync.
+pub const VISION_MAX_TOKENS: usize = 4096;
+
+pub fn is_vision_model(model: &str) -> bool {
+    model.starts_with("gpt-4-vision")
+}
+
+// Cheap proxy for tiktoken's BPE token count.
+pub fn estimate_tokens(message: &ChatMessage) -> usize {
+    message.content.len() / 4 + PER_MESSAGE_OVERHEAD
+}
+
+fn sum_tokens<'a>(messages: impl IntoIterator<Item = &'a ChatMessage>) -> usize {
+    messages.into_iter().map(estimate_tokens).sum()
+}
+
+// Per-model max context window. Unknown/custom models fall back to
+// gpt-3.5-turbo's window since we have no way to query the provider.
+pub fn max_context_tokens(model: &str) -> usize {
+    match model {
+        "gpt-4" | "gpt-4-0314" => 8192,
+        "gpt-4-32k" | "gpt-4-32k-0314" => 32768,
+        _ if is_vision_model(model) => 128_000,
+        _ => 4096,
+    }
+}
+
+// Reply budget to reserve when trimming history, matching the max_tokens
+// ducky actually requests for model.
+pub fn reserved_completion_tokens(model: &str) -> usize {
+    if is_vision_model(model) {
+        VISION_MAX_TOKENS
+    } else {
+        RESERVED_COMPLETION_TOKENS
+    }
+}
+
+// Picks the newest trailing window of history that fits alongside the
+// already-kept context within the model's token budget. History entries
+// already present in kept (e.g. a persisted system message) are excluded
+// from the window first, so they're never double-counted/duplicated
+// alongside the kept copy. The newest remaining message is always included,
+// even if it alone approaches or exceeds the budget, in which case a
+// warning is printed to stderr.
+pub fn trim_history_to_budget(
+    kept: &[ChatMessage],
+    history: &[ChatMessage],
+    model: &str,
+    reserved_completion_tokens: usize,
+) -> Vec<ChatMessage> {
+    let is_kept = |msg: &ChatMessage| {
+        kept.iter()
+            .any(|k| k.role == msg.role && k.content == msg.content)
+    };
+    let candidates: Vec<&ChatMessage> = history.iter().filter(|msg| !is_kept(msg)).collect();
+
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+
+    let max_context = max_context_tokens(model);
+    let budget = max_context
+        .saturating_sub(reserved_completion_tokens)
+        .saturating_sub(sum_tokens(kept));
+
+    let last = candidates.len() - 1;
+    let mut start = last;
+    let mut used = estimate_tokens(candidates[last]);
+
+    if used > budget {
+        eprintln!(
+            "warning: the newest message alone (~{} tokens) approaches or exceeds the \
+             available context budget (~{} tokens) for model {}; sending it anyway",
+            used, budget, model
+        );
+    }
+
+    for i in (0..last).rev() {
+        let cost = estimate_tokens(candidates[i]);
+        if used + cost > budget {
+            break;
+        }
+        used += cost;
+        start = i;
+    }
+
+    candidates[start..].iter().map(|msg| (*msg).clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatgpt::types::Role;
+
+    fn msg(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    // ChatMessage (from the chatgpt crate) isn't known to derive PartialEq/Debug,
+    // so compare windows by (role, content) pairs instead.
+    fn fingerprints(messages: &[ChatMessage]) -> Vec<(String, String)> {
+        messages
+            .iter()
+            .map(|m| (format!("{:?}", m.role), m.content.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn max_context_tokens_covers_known_models() {
+        assert_eq!(max_context_tokens("gpt-4"), 8192);
+        assert_eq!(max_context_tokens("gpt-4-32k"), 32768);
+        assert_eq!(max_context_tokens("gpt-4-vision-preview"), 128_000);
+        assert_eq!(max_context_tokens("gpt-3.5-turbo"), 4096);
+        assert_eq!(max_context_tokens("some-custom-model"), 4096);
+    }
+
+    #[test]
+    fn reserved_completion_tokens_matches_vision_max_tokens() {
+        assert_eq!(
+            reserved_completion_tokens("gpt-4-vision-preview"),
+            VISION_MAX_TOKENS
+        );
+        assert_eq!(
+            reserved_completion_tokens("gpt-3.5-turbo"),
+            RESERVED_COMPLETION_TOKENS
+        );
+    }
+
+    #[test]
+    fn trim_history_to_budget_always_keeps_newest_message() {
+        let history = vec![msg(Role::User, &"x".repeat(100_000))];
+        let window = trim_history_to_budget(&[], &history, "gpt-3.5-turbo", RESERVED_COMPLETION_TOKENS);
+
+        assert_eq!(fingerprints(&window), fingerprints(&history));
+    }
+
+    #[test]
+    fn trim_history_to_budget_drops_oldest_messages_over_budget() {
+        let history = vec![
+            msg(Role::User, &"a".repeat(8_000)),
+            msg(Role::Assistant, &"b".repeat(8_000)),
+            msg(Role::User, "latest"),
+        ];
+
+        let window = trim_history_to_budget(&[], &history, "gpt-3.5-turbo", RESERVED_COMPLETION_TOKENS);
+
+        assert_eq!(fingerprints(&window), fingerprints(&history[1..]));
+    }
+
+    #[test]
+    fn trim_history_to_budget_excludes_already_kept_messages() {
+        let system = msg(Role::System, "you are a helpful shell assistant");
+        let kept = vec![system.clone()];
+        let history = vec![system, msg(Role::User, "hi"), msg(Role::Assistant, "hello")];
+
+        let window = trim_history_to_budget(&kept, &history, "gpt-3.5-turbo", RESERVED_COMPLETION_TOKENS);
+
+        assert_eq!(fingerprints(&window), fingerprints(&history[1..]));
+    }
+}
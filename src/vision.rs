@@ -0,0 +1,48 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use base64::Engine;
+use serde_json::json;
+
+// Reads an image file and encodes it as a data:<mime>;base64,... URL.
+pub fn image_data_url(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path)?;
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+
+    Ok(format!("data:{};base64,{}", mime, encoded))
+}
+
+// Builds ChatMessage.content for a prompt that may reference local images.
+// With no images this is just text. With images, it's a JSON-encoded array
+// of OpenAI vision content-part objects (one text part plus one image_url
+// part per image); Namespace::send_vision_request detects this shape and
+// sends it as structured content instead of literal text.
+pub fn build_message_content(text: &str, images: &[PathBuf]) -> Result<String> {
+    if images.is_empty() {
+        return Ok(text.to_string());
+    }
+
+    let mut parts = vec![json!({ "type": "text", "text": text })];
+    for path in images {
+        let url = image_data_url(path)?;
+        parts.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+    }
+
+    Ok(serde_json::to_string(&parts)?)
+}
+
+// Whether content is the JSON content-parts array build_message_content
+// produces, as opposed to plain text that merely happens to parse as JSON
+// (a bare number, "true"/"null", or a pasted JSON snippet).
+pub fn is_content_parts(content: &str) -> bool {
+    match serde_json::from_str::<serde_json::Value>(content) {
+        Ok(serde_json::Value::Array(parts)) => {
+            !parts.is_empty()
+                && parts
+                    .iter()
+                    .all(|part| part.get("type").and_then(|t| t.as_str()).is_some())
+        }
+        _ => false,
+    }
+}